@@ -0,0 +1,388 @@
+// Headless, deterministic export of an animation to a fragmented MP4 file,
+// driven by `Flux::render_offline` stepping the simulation at synthetic,
+// evenly-spaced timestamps instead of wall-clock time.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+// How many frames to buffer into a single moof/mdat fragment. Keeping
+// fragments short bounds memory use and lets players start decoding before
+// the whole file has been written.
+const DEFAULT_FRAMES_PER_FRAGMENT: u32 = 60;
+
+// Uncompressed RGBA has no standard codec fourcc; name it plainly rather
+// than claim to be a real codec like 'avc1'.
+const SAMPLE_FORMAT: &[u8; 4] = b"RGBA";
+
+const TRACK_ID: u32 = 1;
+
+pub struct ExportSettings {
+    pub duration_secs: f32,
+    pub target_fps: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// Writes raw RGBA framebuffer readbacks into a fragmented ISO BMFF (MP4)
+// container: an initial `ftyp`/`moov` describing one video track, followed
+// by a `moof`/`mdat` pair per fragment.
+pub struct Fmp4Writer {
+    out: File,
+    width: u32,
+    height: u32,
+    target_fps: u32,
+    frames_per_fragment: u32,
+
+    sequence_number: u32,
+    frames_in_fragment: u32,
+    frame_count: u64,
+    fragment_buffer: Vec<u8>,
+}
+
+impl Fmp4Writer {
+    pub fn create(output: impl AsRef<Path>, settings: &ExportSettings) -> Result<Self, Problem> {
+        let mut out = File::create(output).map_err(Problem::Io)?;
+        write_box(&mut out, b"ftyp", |body| {
+            body.extend_from_slice(b"isom");
+            body.extend_from_slice(&0u32.to_be_bytes());
+            body.extend_from_slice(b"isomiso5mp42");
+        })
+        .map_err(Problem::Io)?;
+        write_moov_box(
+            &mut out,
+            settings.width,
+            settings.height,
+            settings.target_fps,
+        )
+        .map_err(Problem::Io)?;
+
+        Ok(Fmp4Writer {
+            out,
+            width: settings.width,
+            height: settings.height,
+            target_fps: settings.target_fps,
+            frames_per_fragment: DEFAULT_FRAMES_PER_FRAGMENT,
+
+            sequence_number: 0,
+            frames_in_fragment: 0,
+            frame_count: 0,
+            fragment_buffer: Vec::new(),
+        })
+    }
+
+    // Appends one RGBA frame, as read back from the GL framebuffer, to the
+    // current fragment and flushes the fragment once it's full.
+    pub fn write_frame(&mut self, rgba: &[u8]) -> Result<(), Problem> {
+        let expected_len = (self.width * self.height * 4) as usize;
+        if rgba.len() != expected_len {
+            return Err(Problem::UnexpectedFrameSize {
+                expected: expected_len,
+                got: rgba.len(),
+            });
+        }
+
+        self.fragment_buffer.extend_from_slice(rgba);
+        self.frames_in_fragment += 1;
+        self.frame_count += 1;
+
+        if self.frames_in_fragment >= self.frames_per_fragment {
+            self.flush_fragment()?;
+        }
+
+        Ok(())
+    }
+
+    // Flushes any buffered frames as a final, possibly short, fragment and
+    // closes out the file.
+    pub fn finish(mut self) -> Result<(), Problem> {
+        if self.frames_in_fragment > 0 {
+            self.flush_fragment()?;
+        }
+        self.out.flush().map_err(Problem::Io)
+    }
+
+    fn flush_fragment(&mut self) -> Result<(), Problem> {
+        self.sequence_number += 1;
+        let base_frame = self.frame_count - self.frames_in_fragment as u64;
+        let frame_byte_size = self.width * self.height * 4;
+
+        write_moof_box(
+            &mut self.out,
+            self.sequence_number,
+            base_frame,
+            self.frames_in_fragment,
+            frame_byte_size,
+        )
+        .map_err(Problem::Io)?;
+        write_box(&mut self.out, b"mdat", |body| {
+            body.extend_from_slice(&self.fragment_buffer)
+        })
+        .map_err(Problem::Io)?;
+
+        self.fragment_buffer.clear();
+        self.frames_in_fragment = 0;
+
+        Ok(())
+    }
+}
+
+// The `moov` box for a fragmented track: a `trak` whose own sample tables
+// (`stts`/`stsc`/`stsz`/`stco`) stay empty, since samples are described
+// per-fragment by `moof`/`traf`/`trun` instead, plus the `mvex`/`trex` pair
+// that marks the track as fragmented in the first place.
+fn write_moov_box(out: &mut File, width: u32, height: u32, fps: u32) -> io::Result<()> {
+    write_box(out, b"moov", |moov| {
+        write_box(moov, b"mvhd", |body| write_mvhd(body, fps)).ok();
+        write_box(moov, b"trak", |trak| {
+            write_box(trak, b"tkhd", |body| write_tkhd(body, width, height)).ok();
+            write_box(trak, b"mdia", |mdia| {
+                write_box(mdia, b"mdhd", |body| write_mdhd(body, fps)).ok();
+                write_box(mdia, b"hdlr", |body| write_hdlr(body)).ok();
+                write_box(mdia, b"minf", |minf| {
+                    write_box(minf, b"vmhd", |body| {
+                        body.extend_from_slice(&[0, 0, 0, 1]); // version/flags
+                        body.extend_from_slice(&[0u8; 8]); // graphicsmode + opcolor
+                    })
+                    .ok();
+                    write_box(minf, b"dinf", |dinf| {
+                        write_box(dinf, b"dref", |body| {
+                            body.extend_from_slice(&0u32.to_be_bytes()); // version/flags
+                            body.extend_from_slice(&1u32.to_be_bytes()); // entry count
+                            write_box(body, b"url ", |url_body| {
+                                url_body.extend_from_slice(&1u32.to_be_bytes());
+                                // self-contained
+                            })
+                            .ok();
+                        })
+                        .ok();
+                    })
+                    .ok();
+                    write_box(minf, b"stbl", |stbl| {
+                        write_box(stbl, b"stsd", |body| write_stsd(body, width, height)).ok();
+                        write_box(stbl, b"stts", |body| write_empty_table(body)).ok();
+                        write_box(stbl, b"stsc", |body| write_empty_table(body)).ok();
+                        write_box(stbl, b"stsz", |body| {
+                            body.extend_from_slice(&0u32.to_be_bytes()); // version/flags
+                            body.extend_from_slice(&0u32.to_be_bytes()); // uniform sample size
+                            body.extend_from_slice(&0u32.to_be_bytes()); // sample count
+                        })
+                        .ok();
+                        write_box(stbl, b"stco", |body| write_empty_table(body)).ok();
+                    })
+                    .ok();
+                })
+                .ok();
+            })
+            .ok();
+        })
+        .ok();
+        write_box(moov, b"mvex", |mvex| {
+            write_box(mvex, b"trex", |body| {
+                body.extend_from_slice(&0u32.to_be_bytes()); // version/flags
+                body.extend_from_slice(&TRACK_ID.to_be_bytes());
+                body.extend_from_slice(&1u32.to_be_bytes()); // default sample description index
+                body.extend_from_slice(&1u32.to_be_bytes()); // default sample duration
+                body.extend_from_slice(&0u32.to_be_bytes()); // default sample size
+                body.extend_from_slice(&0u32.to_be_bytes()); // default sample flags
+            })
+            .ok();
+        })
+        .ok();
+    })
+}
+
+fn write_mvhd(body: &mut Vec<u8>, fps: u32) {
+    body.extend_from_slice(&0u32.to_be_bytes()); // version/flags
+    body.extend_from_slice(&0u32.to_be_bytes()); // creation time
+    body.extend_from_slice(&0u32.to_be_bytes()); // modification time
+    body.extend_from_slice(&fps.to_be_bytes()); // timescale: one tick per frame
+    body.extend_from_slice(&0u32.to_be_bytes()); // duration: unknown up front (fragmented)
+    body.extend_from_slice(&0x0001_0000u32.to_be_bytes()); // rate, 1.0 fixed-point
+    body.extend_from_slice(&0x0100u16.to_be_bytes()); // volume, 1.0 fixed-point
+    body.extend_from_slice(&[0u8; 10]); // reserved
+    body.extend_from_slice(&identity_matrix());
+    body.extend_from_slice(&[0u8; 24]); // pre-defined
+    body.extend_from_slice(&(TRACK_ID + 1).to_be_bytes()); // next track id
+}
+
+fn write_tkhd(body: &mut Vec<u8>, width: u32, height: u32) {
+    body.extend_from_slice(&0x0000_0007u32.to_be_bytes()); // version/flags: enabled+in movie+in preview
+    body.extend_from_slice(&0u32.to_be_bytes()); // creation time
+    body.extend_from_slice(&0u32.to_be_bytes()); // modification time
+    body.extend_from_slice(&TRACK_ID.to_be_bytes());
+    body.extend_from_slice(&0u32.to_be_bytes()); // reserved
+    body.extend_from_slice(&0u32.to_be_bytes()); // duration: unknown up front
+    body.extend_from_slice(&[0u8; 8]); // reserved
+    body.extend_from_slice(&0u16.to_be_bytes()); // layer
+    body.extend_from_slice(&0u16.to_be_bytes()); // alternate group
+    body.extend_from_slice(&0u16.to_be_bytes()); // volume (video track)
+    body.extend_from_slice(&[0u8; 2]); // reserved
+    body.extend_from_slice(&identity_matrix());
+    body.extend_from_slice(&((width as u32) << 16).to_be_bytes()); // width, 16.16 fixed-point
+    body.extend_from_slice(&((height as u32) << 16).to_be_bytes()); // height, 16.16 fixed-point
+}
+
+fn write_mdhd(body: &mut Vec<u8>, fps: u32) {
+    body.extend_from_slice(&0u32.to_be_bytes()); // version/flags
+    body.extend_from_slice(&0u32.to_be_bytes()); // creation time
+    body.extend_from_slice(&0u32.to_be_bytes()); // modification time
+    body.extend_from_slice(&fps.to_be_bytes()); // timescale
+    body.extend_from_slice(&0u32.to_be_bytes()); // duration: unknown up front
+    body.extend_from_slice(&0x55c4u16.to_be_bytes()); // language: und
+    body.extend_from_slice(&0u16.to_be_bytes()); // pre-defined
+}
+
+fn write_hdlr(body: &mut Vec<u8>) {
+    body.extend_from_slice(&0u32.to_be_bytes()); // version/flags
+    body.extend_from_slice(&0u32.to_be_bytes()); // pre-defined
+    body.extend_from_slice(b"vide");
+    body.extend_from_slice(&[0u8; 12]); // reserved
+    body.extend_from_slice(b"FluxOfflineExport\0");
+}
+
+fn write_stsd(body: &mut Vec<u8>, width: u32, height: u32) {
+    body.extend_from_slice(&0u32.to_be_bytes()); // version/flags
+    body.extend_from_slice(&1u32.to_be_bytes()); // entry count
+    write_box(body, SAMPLE_FORMAT, |entry| {
+        entry.extend_from_slice(&[0u8; 6]); // reserved
+        entry.extend_from_slice(&1u16.to_be_bytes()); // data reference index
+        entry.extend_from_slice(&[0u8; 16]); // pre-defined + reserved
+        entry.extend_from_slice(&(width as u16).to_be_bytes());
+        entry.extend_from_slice(&(height as u16).to_be_bytes());
+        entry.extend_from_slice(&0x0048_0000u32.to_be_bytes()); // horizresolution, 72 dpi
+        entry.extend_from_slice(&0x0048_0000u32.to_be_bytes()); // vertresolution, 72 dpi
+        entry.extend_from_slice(&0u32.to_be_bytes()); // reserved
+        entry.extend_from_slice(&1u16.to_be_bytes()); // frame count per sample
+        entry.extend_from_slice(&[0u8; 32]); // compressor name
+        entry.extend_from_slice(&0x0018u16.to_be_bytes()); // depth: 24
+        entry.extend_from_slice(&(-1i16).to_be_bytes()); // pre-defined
+    })
+    .ok();
+}
+
+fn write_empty_table(body: &mut Vec<u8>) {
+    body.extend_from_slice(&0u32.to_be_bytes()); // version/flags
+    body.extend_from_slice(&0u32.to_be_bytes()); // entry count
+}
+
+fn identity_matrix() -> [u8; 36] {
+    let mut matrix = [0u8; 36];
+    matrix[0..4].copy_from_slice(&0x0001_0000u32.to_be_bytes());
+    matrix[16..20].copy_from_slice(&0x0001_0000u32.to_be_bytes());
+    matrix[32..36].copy_from_slice(&0x4000_0000u32.to_be_bytes());
+    matrix
+}
+
+// The `moof`/`traf` fragment header: `mfhd` ties the fragment to its
+// sequence number, `tfhd`/`tfdt` anchor it to the track and its starting
+// decode time, and `trun` lists its samples (one per frame, all the same
+// size since frames are uncompressed). `trun`'s `data_offset` field points
+// into the `mdat` that immediately follows; since that offset depends on
+// this box's own final size, `trun` is built separately and patched once
+// `mfhd`/`traf` are assembled and the total is known.
+fn write_moof_box(
+    out: &mut File,
+    sequence_number: u32,
+    base_frame: u64,
+    frame_count: u32,
+    frame_byte_size: u32,
+) -> io::Result<()> {
+    let mut mfhd = Vec::new();
+    write_box(&mut mfhd, b"mfhd", |body| {
+        body.extend_from_slice(&0u32.to_be_bytes()); // version/flags
+        body.extend_from_slice(&sequence_number.to_be_bytes());
+    })?;
+
+    let mut tfhd = Vec::new();
+    write_box(&mut tfhd, b"tfhd", |body| {
+        body.extend_from_slice(&0u32.to_be_bytes()); // version/flags
+        body.extend_from_slice(&TRACK_ID.to_be_bytes());
+    })?;
+
+    let mut tfdt = Vec::new();
+    write_box(&mut tfdt, b"tfdt", |body| {
+        body.extend_from_slice(&0x0100_0000u32.to_be_bytes()); // version 1: 64-bit base time
+        body.extend_from_slice(&base_frame.to_be_bytes());
+    })?;
+
+    let (trun_body, data_offset_in_body) = build_trun_body(frame_count, frame_byte_size);
+    let mut trun = Vec::new();
+    write_box(&mut trun, b"trun", |body| {
+        body.extend_from_slice(&trun_body)
+    })?;
+
+    let mut traf = Vec::new();
+    write_box(&mut traf, b"traf", |body| {
+        body.extend_from_slice(&tfhd);
+        body.extend_from_slice(&tfdt);
+        body.extend_from_slice(&trun);
+    })?;
+
+    // Where the `trun`'s `data_offset` field landed inside `traf`, after
+    // `traf`'s own 8-byte header and the `tfhd`/`tfdt` boxes ahead of
+    // `trun`, plus `trun`'s own 8-byte header.
+    let data_offset_in_traf = 8 + tfhd.len() + tfdt.len() + 8 + data_offset_in_body;
+
+    let moof_size = 8 + mfhd.len() + traf.len();
+    // Relative to the start of `moof`, straight past the `mdat` box header
+    // that follows it, to the first byte of this fragment's sample data.
+    let data_offset_value = moof_size as i32 + 8;
+    traf[data_offset_in_traf..data_offset_in_traf + 4]
+        .copy_from_slice(&data_offset_value.to_be_bytes());
+
+    out.write_all(&(moof_size as u32).to_be_bytes())?;
+    out.write_all(b"moof")?;
+    out.write_all(&mfhd)?;
+    out.write_all(&traf)
+}
+
+// Builds the raw (unboxed) body of a `trun` box and returns the offset of
+// its `data_offset` field within that body, so `write_moof_box` can patch
+// it once the full `moof` box's size is known.
+fn build_trun_body(frame_count: u32, frame_byte_size: u32) -> (Vec<u8>, usize) {
+    let mut body = Vec::new();
+    // flags: data-offset-present | sample-duration-present | sample-size-present
+    body.extend_from_slice(&0x0000_0301u32.to_be_bytes());
+    body.extend_from_slice(&frame_count.to_be_bytes());
+    let data_offset_position = body.len();
+    body.extend_from_slice(&0i32.to_be_bytes()); // patched by write_moof_box
+    for _ in 0..frame_count {
+        body.extend_from_slice(&1u32.to_be_bytes()); // sample duration: one tick
+        body.extend_from_slice(&frame_byte_size.to_be_bytes());
+    }
+    (body, data_offset_position)
+}
+
+fn write_box(
+    out: &mut impl Write,
+    box_type: &[u8; 4],
+    fill: impl FnOnce(&mut Vec<u8>),
+) -> io::Result<()> {
+    let mut body = Vec::new();
+    fill(&mut body);
+
+    let size = 8 + body.len() as u32;
+    out.write_all(&size.to_be_bytes())?;
+    out.write_all(box_type)?;
+    out.write_all(&body)
+}
+
+#[derive(Debug)]
+pub enum Problem {
+    Io(io::Error),
+    UnexpectedFrameSize { expected: usize, got: usize },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Problem::*;
+        match self {
+            Io(err) => write!(f, "{}", err),
+            UnexpectedFrameSize { expected, got } => {
+                write!(f, "expected a frame of {} bytes, got {}", expected, got)
+            }
+        }
+    }
+}
@@ -1,8 +1,14 @@
-use crate::{drawer, fluid, noise, render, settings};
+use crate::{drawer, export, fluid, media_sync, noise, render, settings, stats, timeline};
 use drawer::Drawer;
+use export::Fmp4Writer;
 use fluid::Fluid;
+use media_sync::{Discontinuity, MediaSync};
 use noise::NoiseGenerator;
 use settings::Settings;
+use stats::{AdaptiveQuality, Stats};
+use timeline::Timeline;
+
+use std::path::Path;
 
 use glow::HasContext;
 use std::fmt;
@@ -12,6 +18,13 @@ use std::rc::Rc;
 const MAX_ELAPSED_TIME: f32 = 1000.0;
 const MAX_FRAME_TIME: f32 = 1.0 / 10.0;
 
+// A ceiling on how many fluid substeps a single `step` call will run. Without
+// this, a large `frame_time` (e.g. after a media clock jumps forward just
+// under the discontinuity threshold) would force the `while` loop below
+// through a burst of substeps in one frame instead of spreading the backlog
+// across several, as if the simulation had locked up.
+const MAX_CATCHUP_SUBSTEPS: u32 = 8;
+
 pub struct Flux {
     fluid: Fluid,
     drawer: Drawer,
@@ -28,16 +41,117 @@ pub struct Flux {
 
     frame_time: f32,
     fluid_timestep: f32,
+
+    // The velocity field from the most recently completed fluid simulation
+    // step. Kept alongside the current step's velocity field so line
+    // placement can interpolate between the two instead of snapping to
+    // whichever step last finished.
+    previous_velocity: render::Framebuffer,
+
+    // An optional keyframed settings timeline. When set, it takes over from
+    // whatever `Settings` was last passed to `update` and drives `settings`
+    // every frame based on `elapsed_time`.
+    timeline: Option<Timeline>,
+
+    // Set once the caller starts driving Flux with `animate_media_time`
+    // instead of `animate`, so incoming timestamps are treated as a seekable
+    // media running time rather than host time.
+    media_sync: Option<MediaSync>,
+
+    stats: Stats,
+    adaptive_quality: Option<AdaptiveQuality>,
+
+    // Two GPU timer queries, ping-ponged frame to frame, covering the fluid
+    // substeps, noise generation, and draw calls issued by `step`. Read back
+    // at the start of the frame after next rather than right after
+    // `end_query` so we don't stall the pipeline; ping-ponging instead of
+    // reusing a single query object means a still-in-flight query is never
+    // clobbered by the next frame's `begin_query`.
+    gpu_timer_queries: [<glow::Context as HasContext>::Query; 2],
+    gpu_timer_write_index: usize,
+    gpu_timer_pending: [bool; 2],
 }
 
 impl Flux {
     pub fn update(&mut self, settings: &Rc<Settings>) -> () {
         self.settings = Rc::clone(settings);
+
+        // Adaptive quality owns `fluid_timestep` while it's active (see
+        // `poll_adaptive_quality`); don't let a settings update stomp its
+        // chosen rate back to whatever `settings` says.
+        if self.adaptive_quality.is_none() {
+            self.fluid_timestep = 1.0 / self.settings.fluid_simulation_frame_rate;
+        }
+
         self.fluid.update(&self.settings);
         self.drawer.update(&self.settings);
         self.noise_generator.update(&self.settings.noise_channels);
     }
 
+    // Hands control of `settings` over to a keyframed timeline. From the
+    // next `animate` call onward, the settings applied each frame come from
+    // interpolating the timeline's keyframes at `elapsed_time` rather than
+    // from the last `Settings` passed to `update`.
+    pub fn set_timeline(&mut self, timeline: Timeline) {
+        self.timeline = Some(timeline);
+    }
+
+    // Releases the timeline, leaving whatever `Settings` it last produced in
+    // effect until the next manual `update`.
+    pub fn stop_timeline(&mut self) {
+        self.timeline = None;
+    }
+
+    // Measured display FPS, fluid substep count, and GPU frame time from
+    // recent frames, for embedders that want to display or log performance.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    // Enables automatically lowering (and later restoring)
+    // `fluid_simulation_frame_rate` when the fluid substep loop keeps
+    // saturating `MAX_CATCHUP_SUBSTEPS`, which indicates the device can't
+    // keep up at the configured rate. The rate is never pushed outside
+    // `[min_fluid_simulation_frame_rate, max_fluid_simulation_frame_rate]`.
+    pub fn enable_adaptive_quality(
+        &mut self,
+        min_fluid_simulation_frame_rate: f32,
+        max_fluid_simulation_frame_rate: f32,
+    ) {
+        self.adaptive_quality = Some(AdaptiveQuality::new(
+            min_fluid_simulation_frame_rate,
+            max_fluid_simulation_frame_rate,
+        ));
+    }
+
+    pub fn disable_adaptive_quality(&mut self) {
+        self.adaptive_quality = None;
+    }
+
+    // Builds a `Flux` for `render_offline` without requiring the caller to
+    // first stand up a live, windowed GL context: `render::Context` gets a
+    // headless (surfaceless/pbuffer) context of its own instead of one bound
+    // to a window. Use `new` instead when presenting to a real surface.
+    pub fn new_headless(
+        logical_width: u32,
+        logical_height: u32,
+        physical_width: u32,
+        physical_height: u32,
+        settings: &Rc<Settings>,
+    ) -> Result<Flux, Problem> {
+        let context = render::Context::new_headless(physical_width, physical_height)
+            .map_err(Problem::CannotRender)?;
+
+        Flux::new(
+            &context,
+            logical_width,
+            logical_height,
+            physical_width,
+            physical_height,
+            settings,
+        )
+    }
+
     pub fn new(
         context: &render::Context,
         logical_width: u32,
@@ -70,6 +184,13 @@ impl Flux {
             .build()
             .map_err(Problem::CannotRender)?;
 
+        let previous_velocity = fluid.get_velocity().clone();
+
+        let gpu_timer_queries = [
+            unsafe { context.create_query() }.map_err(Problem::CannotCreateGpuTimer)?,
+            unsafe { context.create_query() }.map_err(Problem::CannotCreateGpuTimer)?,
+        ];
+
         Ok(Flux {
             fluid,
             drawer,
@@ -81,6 +202,16 @@ impl Flux {
             elapsed_time: 0.0,
             frame_time: 0.0,
             fluid_timestep: 1.0 / settings.fluid_simulation_frame_rate,
+
+            previous_velocity,
+            timeline: None,
+            media_sync: None,
+
+            stats: Stats::new(),
+            adaptive_quality: None,
+            gpu_timer_queries,
+            gpu_timer_write_index: 0,
+            gpu_timer_pending: [false, false],
         })
     }
 
@@ -108,6 +239,38 @@ impl Flux {
             (0.001 * (timestamp - self.last_timestamp)) as f32,
         );
         self.last_timestamp = timestamp;
+        self.step(timestep);
+    }
+
+    // Drives Flux from an external media clock (e.g. a video or audio track
+    // being visualized) instead of host time. `media_time_secs` is that
+    // track's running time and, unlike `animate`'s timestamp, may jump
+    // backward (a seek or loop point) or forward by more than a normal frame
+    // (a stall followed by catch-up). Call this consistently instead of
+    // `animate` for the lifetime of a sync session.
+    pub fn animate_media_time(&mut self, media_time_secs: f64) {
+        let media_sync = self.media_sync.get_or_insert_with(MediaSync::new);
+        let (timestep, discontinuity) = media_sync.resolve(media_time_secs);
+
+        match discontinuity {
+            Discontinuity::None => {}
+            Discontinuity::Seek => {
+                // Re-anchored on a seek/loop; drop any pending substeps
+                // rather than let them run against the new position.
+                self.frame_time = 0.0;
+            }
+            Discontinuity::Burst => {
+                self.frame_time = 0.0;
+                self.fluid.clear_velocity();
+            }
+        }
+
+        self.step(timestep);
+    }
+
+    fn step(&mut self, timestep: f32) {
+        let gpu_frame_time_ms = self.poll_gpu_timer();
+
         self.elapsed_time += timestep;
         self.frame_time += timestep;
 
@@ -117,7 +280,41 @@ impl Flux {
             self.elapsed_time = timer_overflow;
         }
 
-        while self.frame_time >= self.fluid_timestep {
+        if let Some(timeline) = &self.timeline {
+            let settings = timeline.settings_at(self.elapsed_time);
+            // Outside an interpolation span, `settings_at` hands back the
+            // same `Rc` as last frame (holding at a keyframe); skip the
+            // cascade into `fluid`/`drawer`/`noise_generator` in that case
+            // instead of re-running it on every single animation frame.
+            if !Rc::ptr_eq(&settings, &self.settings) {
+                self.update(&settings);
+            }
+        }
+
+        // Only issue a query into this frame's slot if the last query that
+        // used it has already been read back; otherwise skip timing this
+        // frame rather than discard the still-in-flight query. The query
+        // brackets the fluid substep loop and drawing below, since substeps
+        // are almost always the dominant GPU cost.
+        let query_index = self.gpu_timer_write_index;
+        let can_query_this_frame = !self.gpu_timer_pending[query_index];
+        if can_query_this_frame {
+            unsafe {
+                self.context
+                    .begin_query(glow::TIME_ELAPSED, self.gpu_timer_queries[query_index]);
+            }
+        }
+
+        let mut stepped = false;
+        let mut substeps = 0;
+        while self.frame_time >= self.fluid_timestep && substeps < MAX_CATCHUP_SUBSTEPS {
+            if !stepped {
+                // Stash the velocity field as it stood before this frame's
+                // substeps so we can interpolate towards the new one below.
+                self.previous_velocity = self.fluid.get_velocity().clone();
+                stepped = true;
+            }
+
             self.noise_generator.generate(self.elapsed_time);
 
             self.fluid.advect_forward(self.fluid_timestep);
@@ -133,12 +330,23 @@ impl Flux {
             self.fluid.subtract_gradient();
 
             self.frame_time -= self.fluid_timestep;
+            substeps += 1;
         }
 
-        // TODO: the line animation is still dependent on the client’s fps. Is
-        // this worth fixing?
-        self.drawer
-            .place_lines(&self.fluid.get_velocity(), self.elapsed_time, timestep);
+        // If we hit the catch-up cap, `frame_time` still holds a backlog of
+        // unsimulated time; leave it in place so the next `step` call's loop
+        // picks up where this one left off, spreading the catch-up across
+        // several frames as `MAX_CATCHUP_SUBSTEPS` intends, rather than
+        // discarding it. `alpha` below is still clamped to `[0, 1]` for
+        // interpolation purposes even while that backlog remains.
+        let alpha = (self.frame_time / self.fluid_timestep).clamp(0.0, 1.0);
+        self.drawer.place_lines(
+            &self.previous_velocity,
+            &self.fluid.get_velocity(),
+            alpha,
+            self.elapsed_time,
+            timestep,
+        );
 
         unsafe {
             self.context.clear_color(0.0, 0.0, 0.0, 1.0);
@@ -164,6 +372,137 @@ impl Flux {
                 self.drawer.draw_texture(&self.fluid.get_divergence());
             }
         };
+
+        if can_query_this_frame {
+            unsafe {
+                self.context.end_query(glow::TIME_ELAPSED);
+            }
+            self.gpu_timer_pending[query_index] = true;
+            self.gpu_timer_write_index = 1 - query_index;
+        }
+
+        self.stats
+            .record_frame(timestep, substeps, gpu_frame_time_ms);
+        self.poll_adaptive_quality(substeps);
+    }
+
+    // Reads back the pending GPU timer query in the slot
+    // `gpu_timer_write_index` is about to reuse this frame (issued two
+    // frames ago), if its result has landed yet, rather than waiting on it
+    // right after issuing it (which would stall the CPU on the GPU). Returns
+    // the last known frame time when no new result is available.
+    fn poll_gpu_timer(&mut self) -> f32 {
+        let read_index = self.gpu_timer_write_index;
+        if !self.gpu_timer_pending[read_index] {
+            return self.stats.gpu_frame_time_ms();
+        }
+
+        unsafe {
+            let available = self.context.get_query_parameter_u32(
+                self.gpu_timer_queries[read_index],
+                glow::QUERY_RESULT_AVAILABLE,
+            );
+            if available == 0 {
+                return self.stats.gpu_frame_time_ms();
+            }
+
+            let elapsed_ns = self
+                .context
+                .get_query_parameter_u32(self.gpu_timer_queries[read_index], glow::QUERY_RESULT);
+            self.gpu_timer_pending[read_index] = false;
+            elapsed_ns as f32 / 1_000_000.0
+        }
+    }
+
+    fn poll_adaptive_quality(&mut self, substeps_last_frame: u32) {
+        // The currently effective rate lives in `fluid_timestep`, not
+        // `settings.fluid_simulation_frame_rate`: while adaptive quality is
+        // active, a settings update (e.g. from a `Timeline` keyframe) leaves
+        // `fluid_timestep` alone (see `update`), and adjusting it here directly, rather than by cloning and
+        // re-`update`-ing `settings`, means a later settings update can't
+        // clobber what adaptive quality has chosen.
+        let current_fluid_simulation_frame_rate = 1.0 / self.fluid_timestep;
+
+        let new_frame_rate = match &mut self.adaptive_quality {
+            None => return,
+            Some(adaptive_quality) => adaptive_quality.poll(
+                substeps_last_frame,
+                MAX_CATCHUP_SUBSTEPS,
+                current_fluid_simulation_frame_rate,
+            ),
+        };
+
+        if let Some(new_frame_rate) = new_frame_rate {
+            log::debug!(
+                "Adaptive quality: fluid_simulation_frame_rate {} -> {}",
+                current_fluid_simulation_frame_rate,
+                new_frame_rate
+            );
+            self.fluid_timestep = 1.0 / new_frame_rate;
+        }
+    }
+
+    // Drives the animation in lockstep with a target frame rate instead of
+    // the host's clock, reading back each frame and muxing it into a
+    // fragmented MP4 file. Because the timestamps fed to `animate` are
+    // synthetic and evenly spaced, the output is fully deterministic: the
+    // same settings, duration and starting state always produce the same
+    // video, unlike the
+    // live path where `animate`'s timestep depends on however fast the
+    // client happens to be presenting frames.
+    pub fn render_offline(
+        &mut self,
+        duration_secs: f32,
+        target_fps: u32,
+        output: impl AsRef<Path>,
+    ) -> Result<(), Problem> {
+        // Reset the animation clock and the substep interpolation state
+        // regardless of whatever `animate` calls this `Flux` may have already
+        // seen live — otherwise the first synthetic timestep would be
+        // measured against whatever `last_timestamp` happened to be left at,
+        // and line placement would interpolate from a stale
+        // `previous_velocity`. The fluid's velocity field and the drawer's
+        // line state are not reset; they carry over from earlier frames.
+        self.last_timestamp = 0.0;
+        self.elapsed_time = 0.0;
+        self.frame_time = 0.0;
+        self.previous_velocity = self.fluid.get_velocity().clone();
+
+        // Adaptive quality would change `fluid_timestep` mid-export based on
+        // substep counts and on counters carried over from earlier live
+        // frames; suspend it for the export so `fluid_timestep` stays put and
+        // restore it once done.
+        let suspended_adaptive_quality = self.adaptive_quality.take();
+
+        let (width, height) = self.drawer.physical_size();
+        let export_result = (|| -> Result<(), Problem> {
+            let mut writer = Fmp4Writer::create(
+                output,
+                &export::ExportSettings {
+                    duration_secs,
+                    target_fps,
+                    width,
+                    height,
+                },
+            )
+            .map_err(Problem::CannotExport)?;
+
+            let frame_count = (duration_secs * target_fps as f32).ceil() as u64;
+            let frame_duration_ms = 1000.0 / target_fps as f64;
+
+            for frame in 0..frame_count {
+                let timestamp = frame as f64 * frame_duration_ms;
+                self.animate(timestamp);
+
+                let rgba = self.drawer.read_pixels();
+                writer.write_frame(&rgba).map_err(Problem::CannotExport)?;
+            }
+
+            writer.finish().map_err(Problem::CannotExport)
+        })();
+
+        self.adaptive_quality = suspended_adaptive_quality;
+        export_result
     }
 }
 
@@ -171,6 +510,8 @@ impl Flux {
 pub enum Problem {
     CannotReadSettings(String),
     CannotRender(render::Problem),
+    CannotExport(export::Problem),
+    CannotCreateGpuTimer(String),
 }
 
 impl fmt::Display for Problem {
@@ -179,6 +520,8 @@ impl fmt::Display for Problem {
         match self {
             CannotReadSettings(msg) => write!(f, "{}", msg),
             CannotRender(render_msg) => write!(f, "{}", render_msg.to_string()),
+            CannotExport(export_msg) => write!(f, "{}", export_msg.to_string()),
+            CannotCreateGpuTimer(msg) => write!(f, "{}", msg),
         }
     }
 }
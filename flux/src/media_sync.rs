@@ -0,0 +1,132 @@
+// Turns an external media clock into the timestep `Flux::animate` expects.
+// A media clock can jump backwards (a seek or loop point) or far forwards
+// (a stall followed by catch-up); either would otherwise reach the
+// fixed-timestep accumulator in `Flux::animate` as a huge or negative
+// timestep. Past `DISCONTINUITY_THRESHOLD_SECS` we treat the jump as a seek
+// and re-anchor instead of returning a timestep for that call.
+
+const DISCONTINUITY_THRESHOLD_SECS: f64 = 1.0;
+
+// Forward discontinuities past this are considered big enough that
+// continuing to animate from wherever the simulation happened to be looks
+// more like a glitch than a cut, so the caller is told to clear velocity.
+const BURST_THRESHOLD_SECS: f64 = 5.0;
+
+pub struct MediaSync {
+    reference: Option<(f64, f32)>,
+}
+
+impl MediaSync {
+    pub fn new() -> Self {
+        MediaSync { reference: None }
+    }
+
+    // Resolves an incoming media timestamp (in seconds) to the timestep
+    // that should be fed into the fluid step, alongside a `Discontinuity`
+    // describing whether this call re-anchored the mapping.
+    pub fn resolve(&mut self, media_time_secs: f64) -> (f32, Discontinuity) {
+        let (reference_media_time, reference_elapsed) = match self.reference {
+            None => {
+                self.reference = Some((media_time_secs, 0.0));
+                return (0.0, Discontinuity::None);
+            }
+            Some(reference) => reference,
+        };
+
+        let delta = media_time_secs - reference_media_time;
+
+        if delta < 0.0 || delta > DISCONTINUITY_THRESHOLD_SECS {
+            self.reference = Some((media_time_secs, reference_elapsed));
+
+            let discontinuity = if delta > BURST_THRESHOLD_SECS {
+                Discontinuity::Burst
+            } else {
+                Discontinuity::Seek
+            };
+            return (0.0, discontinuity);
+        }
+
+        // Advance the anchor to this call's timestamp so the next call's
+        // `delta` measures the incremental gap since *this* call, not the
+        // cumulative gap since the mapping was last re-anchored.
+        self.reference = Some((media_time_secs, reference_elapsed + delta as f32));
+        (delta as f32, Discontinuity::None)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Discontinuity {
+    // No jump; the returned timestep can be fed straight into the fixed
+    // timestep accumulator.
+    None,
+
+    // A seek or loop point was detected and the mapping re-anchored. The
+    // returned timestep is zero for this call.
+    Seek,
+
+    // Like `Seek`, but the forward jump was large enough that resuming the
+    // simulation from its old state would look like a visible burst rather
+    // than a cut; callers should consider clearing velocity.
+    Burst,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steady_playback_returns_incremental_deltas() {
+        let mut media_sync = MediaSync::new();
+        assert_eq!(media_sync.resolve(10.0), (0.0, Discontinuity::None));
+
+        for i in 1..=5 {
+            let (timestep, discontinuity) = media_sync.resolve(10.0 + 0.1 * i as f64);
+            assert_eq!(discontinuity, Discontinuity::None);
+            assert!((timestep - 0.1).abs() < 1e-6, "timestep was {}", timestep);
+        }
+    }
+
+    #[test]
+    fn backward_jump_is_a_seek() {
+        let mut media_sync = MediaSync::new();
+        media_sync.resolve(10.0);
+
+        let (timestep, discontinuity) = media_sync.resolve(4.0);
+        assert_eq!(timestep, 0.0);
+        assert_eq!(discontinuity, Discontinuity::Seek);
+    }
+
+    #[test]
+    fn forward_jump_past_threshold_is_a_seek() {
+        let mut media_sync = MediaSync::new();
+        media_sync.resolve(10.0);
+
+        let (timestep, discontinuity) = media_sync.resolve(11.5);
+        assert_eq!(timestep, 0.0);
+        assert_eq!(discontinuity, Discontinuity::Seek);
+    }
+
+    #[test]
+    fn forward_jump_past_burst_threshold_is_a_burst() {
+        let mut media_sync = MediaSync::new();
+        media_sync.resolve(10.0);
+
+        let (timestep, discontinuity) = media_sync.resolve(20.0);
+        assert_eq!(timestep, 0.0);
+        assert_eq!(discontinuity, Discontinuity::Burst);
+    }
+
+    #[test]
+    fn repeated_normal_deltas_do_not_cross_the_threshold() {
+        // Regression test: `resolve` must re-anchor to the incoming
+        // timestamp each call, not the original anchor, or these small
+        // steady deltas would accumulate into a spurious discontinuity.
+        let mut media_sync = MediaSync::new();
+        media_sync.resolve(0.0);
+
+        for i in 1..=20 {
+            let (_, discontinuity) = media_sync.resolve(0.1 * i as f64);
+            assert_eq!(discontinuity, Discontinuity::None);
+        }
+    }
+}
@@ -0,0 +1,233 @@
+// Runtime performance metrics: a small rolling window of recent frame
+// timesteps to report a stable FPS figure, plus the last frame's fluid
+// substep count and measured GPU frame time, which together tell an
+// embedder (or `Flux` itself, in adaptive mode) whether the device is
+// keeping up.
+
+use std::collections::VecDeque;
+
+const ROLLING_WINDOW: usize = 30;
+
+pub struct Stats {
+    recent_timesteps: VecDeque<f32>,
+    substeps_last_frame: u32,
+    gpu_frame_time_ms: f32,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            recent_timesteps: VecDeque::with_capacity(ROLLING_WINDOW),
+            substeps_last_frame: 0,
+            gpu_frame_time_ms: 0.0,
+        }
+    }
+
+    pub(crate) fn record_frame(&mut self, timestep: f32, substeps: u32, gpu_frame_time_ms: f32) {
+        if self.recent_timesteps.len() == ROLLING_WINDOW {
+            self.recent_timesteps.pop_front();
+        }
+        self.recent_timesteps.push_back(timestep);
+
+        self.substeps_last_frame = substeps;
+        self.gpu_frame_time_ms = gpu_frame_time_ms;
+    }
+
+    // The rolling average display FPS over the last `ROLLING_WINDOW` frames.
+    pub fn fps(&self) -> f32 {
+        if self.recent_timesteps.is_empty() {
+            return 0.0;
+        }
+
+        let average_timestep: f32 =
+            self.recent_timesteps.iter().sum::<f32>() / self.recent_timesteps.len() as f32;
+
+        if average_timestep <= 0.0 {
+            0.0
+        } else {
+            1.0 / average_timestep
+        }
+    }
+
+    // How many fluid substeps the most recent `animate` call ran. Pinned at
+    // the catch-up cap frame after frame means the device can't keep up with
+    // `fluid_simulation_frame_rate`.
+    pub fn substeps_last_frame(&self) -> u32 {
+        self.substeps_last_frame
+    }
+
+    pub fn gpu_frame_time_ms(&self) -> f32 {
+        self.gpu_frame_time_ms
+    }
+}
+
+// Watches `Stats` for sustained substep saturation and relaxes simulation
+// quality to compensate, raising it back once the device has headroom
+// again. This trades visual fidelity for keeping the animation smooth
+// instead of entering a death spiral where simulation work for a frame
+// routinely exceeds the time budget for that frame.
+pub struct AdaptiveQuality {
+    min_fluid_simulation_frame_rate: f32,
+    max_fluid_simulation_frame_rate: f32,
+    saturated_frames: u32,
+    idle_frames: u32,
+
+    // Whether we've lowered quality at least once since the last full
+    // recovery. Zero or one substeps per frame is the normal steady state
+    // whenever the display outruns the simulation rate, so it says nothing
+    // about GPU headroom on its own; only once we've actually degraded
+    // quality does a run of quiet frames mean it's safe to restore it.
+    lowered: bool,
+}
+
+// Consecutive saturated/idle frames required before nudging quality, so a
+// single busy or quiet frame doesn't cause thrashing.
+const SATURATION_PATIENCE: u32 = 10;
+const RECOVERY_PATIENCE: u32 = 60;
+const STEP_FACTOR: f32 = 0.8;
+
+impl AdaptiveQuality {
+    pub fn new(min_fluid_simulation_frame_rate: f32, max_fluid_simulation_frame_rate: f32) -> Self {
+        AdaptiveQuality {
+            min_fluid_simulation_frame_rate,
+            max_fluid_simulation_frame_rate,
+            saturated_frames: 0,
+            idle_frames: 0,
+            lowered: false,
+        }
+    }
+
+    // Given the substep count from the last frame and the substep cap it's
+    // measured against, returns an adjusted `fluid_simulation_frame_rate` if
+    // quality should change this frame, or `None` to leave it as-is.
+    pub(crate) fn poll(
+        &mut self,
+        substeps_last_frame: u32,
+        max_substeps: u32,
+        current_fluid_simulation_frame_rate: f32,
+    ) -> Option<f32> {
+        if substeps_last_frame >= max_substeps {
+            self.saturated_frames += 1;
+            self.idle_frames = 0;
+        } else if substeps_last_frame <= 1 {
+            self.idle_frames += 1;
+            self.saturated_frames = 0;
+        } else {
+            self.saturated_frames = 0;
+            self.idle_frames = 0;
+        }
+
+        if self.saturated_frames >= SATURATION_PATIENCE {
+            self.saturated_frames = 0;
+            let lowered = (current_fluid_simulation_frame_rate * STEP_FACTOR)
+                .max(self.min_fluid_simulation_frame_rate);
+            if lowered < current_fluid_simulation_frame_rate {
+                self.lowered = true;
+                return Some(lowered);
+            }
+            return None;
+        }
+
+        // Only claw quality back if we're the ones who took it away; a
+        // healthy device that's simply keeping up (0-1 substeps per display
+        // frame is common whenever the display outpaces the simulation)
+        // shouldn't get nudged above its configured rate.
+        if self.lowered && self.idle_frames >= RECOVERY_PATIENCE {
+            self.idle_frames = 0;
+            let raised = (current_fluid_simulation_frame_rate / STEP_FACTOR)
+                .min(self.max_fluid_simulation_frame_rate);
+            if raised >= self.max_fluid_simulation_frame_rate {
+                self.lowered = false;
+            }
+            return (raised > current_fluid_simulation_frame_rate).then_some(raised);
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sustained_saturation_lowers_quality() {
+        let mut adaptive_quality = AdaptiveQuality::new(10.0, 60.0);
+
+        for _ in 0..SATURATION_PATIENCE - 1 {
+            assert_eq!(adaptive_quality.poll(8, 8, 60.0), None);
+        }
+        assert_eq!(adaptive_quality.poll(8, 8, 60.0), Some(60.0 * STEP_FACTOR));
+    }
+
+    #[test]
+    fn sustained_idle_after_a_drop_recovers_quality() {
+        let mut adaptive_quality = AdaptiveQuality::new(10.0, 60.0);
+
+        for _ in 0..SATURATION_PATIENCE {
+            adaptive_quality.poll(8, 8, 60.0);
+        }
+        let lowered = 60.0 * STEP_FACTOR;
+
+        for _ in 0..RECOVERY_PATIENCE - 1 {
+            assert_eq!(adaptive_quality.poll(1, 8, lowered), None);
+        }
+        assert_eq!(
+            adaptive_quality.poll(1, 8, lowered),
+            Some(lowered / STEP_FACTOR)
+        );
+    }
+
+    #[test]
+    fn idle_without_a_prior_drop_does_not_raise_quality() {
+        // 0-1 substeps per frame is the normal steady state whenever the
+        // display outpaces the simulation; it shouldn't be read as headroom
+        // to push the rate above what was configured.
+        let mut adaptive_quality = AdaptiveQuality::new(10.0, 60.0);
+
+        for _ in 0..RECOVERY_PATIENCE * 2 {
+            assert_eq!(adaptive_quality.poll(1, 8, 60.0), None);
+        }
+    }
+
+    #[test]
+    fn quality_never_drops_below_the_floor() {
+        let mut adaptive_quality = AdaptiveQuality::new(10.0, 60.0);
+        let mut rate = 11.0;
+
+        loop {
+            let mut dropped = None;
+            for _ in 0..SATURATION_PATIENCE {
+                dropped = adaptive_quality.poll(8, 8, rate);
+            }
+            match dropped {
+                Some(new_rate) => rate = new_rate,
+                None => break,
+            }
+        }
+
+        assert_eq!(rate, 10.0);
+    }
+
+    #[test]
+    fn quality_never_rises_above_the_ceiling() {
+        let mut adaptive_quality = AdaptiveQuality::new(10.0, 60.0);
+        for _ in 0..SATURATION_PATIENCE {
+            adaptive_quality.poll(8, 8, 60.0);
+        }
+        let mut rate = 60.0 * STEP_FACTOR;
+
+        loop {
+            let mut raised = None;
+            for _ in 0..RECOVERY_PATIENCE {
+                raised = adaptive_quality.poll(1, 8, rate);
+            }
+            match raised {
+                Some(new_rate) => rate = new_rate,
+                None => break,
+            }
+        }
+
+        assert_eq!(rate, 60.0);
+    }
+}
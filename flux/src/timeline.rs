@@ -0,0 +1,141 @@
+// Animates `Settings` over time by interpolating between user-supplied
+// keyframes: the caller scripts a scene as a handful of `Settings` snapshots
+// pinned to timestamps, and everything in between is filled in automatically.
+
+use crate::noise::Channel;
+use crate::settings::Settings;
+use std::rc::Rc;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Easing {
+    Linear,
+    EaseInOut,
+}
+
+impl Easing {
+    fn apply(self, t: f32) -> f32 {
+        match self {
+            Easing::Linear => t,
+            Easing::EaseInOut => t * t * (3.0 - 2.0 * t),
+        }
+    }
+}
+
+pub struct Keyframe {
+    pub time: f32,
+    pub settings: Rc<Settings>,
+    pub easing: Easing,
+}
+
+impl Keyframe {
+    pub fn new(time: f32, settings: Rc<Settings>, easing: Easing) -> Self {
+        Keyframe {
+            time,
+            settings,
+            easing,
+        }
+    }
+}
+
+// An ordered list of `Settings` keyframes. `settings_at` is expected to be
+// called once per `animate` with the current `elapsed_time` and produces the
+// interpolated `Settings` to feed into `Flux::update`.
+pub struct Timeline {
+    keyframes: Vec<Keyframe>,
+}
+
+impl Timeline {
+    pub fn new(mut keyframes: Vec<Keyframe>) -> Self {
+        assert!(
+            !keyframes.is_empty(),
+            "Timeline must have at least one keyframe"
+        );
+        keyframes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+        Timeline { keyframes }
+    }
+
+    // Finds the keyframes surrounding `elapsed_time` and returns the
+    // `Settings` interpolated between them. Time before the first keyframe
+    // or after the last one holds at that keyframe's settings, so a timeline
+    // that doesn't span the full `MAX_ELAPSED_TIME` loop still behaves
+    // sensibly.
+    pub fn settings_at(&self, elapsed_time: f32) -> Rc<Settings> {
+        match self.keyframes.as_slice() {
+            [] => unreachable!("Timeline::new rejects an empty keyframe list"),
+            [only] => Rc::clone(&only.settings),
+            keyframes => {
+                if elapsed_time <= keyframes[0].time {
+                    return Rc::clone(&keyframes[0].settings);
+                }
+                if elapsed_time >= keyframes[keyframes.len() - 1].time {
+                    return Rc::clone(&keyframes[keyframes.len() - 1].settings);
+                }
+
+                let next_index = keyframes
+                    .iter()
+                    .position(|keyframe| keyframe.time > elapsed_time)
+                    .unwrap();
+                let from = &keyframes[next_index - 1];
+                let to = &keyframes[next_index];
+
+                let span = to.time - from.time;
+                let t = if span > 0.0 {
+                    (elapsed_time - from.time) / span
+                } else {
+                    1.0
+                };
+
+                Rc::new(from.settings.lerp(&to.settings, from.easing.apply(t)))
+            }
+        }
+    }
+}
+
+fn lerp_f32(from: f32, to: f32, t: f32) -> f32 {
+    from + (to - from) * t
+}
+
+impl Channel {
+    // Only the channel's amplitude is interpolated; its other fields snap at
+    // the keyframe boundary along with the rest of `Settings`.
+    fn lerp(&self, to: &Channel, t: f32) -> Channel {
+        let snapped = if t >= 1.0 { to.clone() } else { self.clone() };
+
+        Channel {
+            amplitude: lerp_f32(self.amplitude, to.amplitude, t),
+            ..snapped
+        }
+    }
+}
+
+impl Settings {
+    // Produces a `Settings` that is the interpolation of `self` and `to` at
+    // `t` in `[0, 1]`. Only the numeric simulation/rendering fields listed
+    // below are lerped — color/palette is out of scope for this lerp, not
+    // merely unimplemented. Every other field, including `mode`, snaps from
+    // `self` to `to` once the keyframe boundary (`t >= 1.0`) is reached,
+    // rather than jumping the instant `t` leaves 0.
+    pub fn lerp(&self, to: &Settings, t: f32) -> Settings {
+        let snapped = if t >= 1.0 { to.clone() } else { self.clone() };
+
+        Settings {
+            line_width: lerp_f32(self.line_width, to.line_width, t),
+            line_length: lerp_f32(self.line_length, to.line_length, t),
+            line_begin_offset: lerp_f32(self.line_begin_offset, to.line_begin_offset, t),
+            viscosity: lerp_f32(self.viscosity, to.viscosity, t),
+            velocity_dissipation: lerp_f32(self.velocity_dissipation, to.velocity_dissipation, t),
+            fluid_simulation_frame_rate: lerp_f32(
+                self.fluid_simulation_frame_rate,
+                to.fluid_simulation_frame_rate,
+                t,
+            ),
+            noise_channels: self
+                .noise_channels
+                .iter()
+                .zip(to.noise_channels.iter())
+                .map(|(from_channel, to_channel)| from_channel.lerp(to_channel, t))
+                .collect(),
+            ..snapped
+        }
+    }
+}